@@ -3,15 +3,25 @@ use rspotify::{
     prelude::{BaseClient, OAuthClient},
     scopes, AuthCodePkceSpotify, Config, Credentials, OAuth,
 };
+use std::path::PathBuf;
 
-pub async fn get_client() -> Result<impl BaseClient + OAuthClient> {
-    let Some(creds) = Credentials::from_env() else { bail!("Credentials::from_env failed.") };
+pub async fn get_client(token_cache_path: PathBuf) -> Result<impl BaseClient + OAuthClient> {
+    let Some(creds) = Credentials::from_env() else {
+        bail!("Credentials::from_env failed.")
+    };
 
-    let scopes = scopes!("user-library-read");
-    let Some(oauth) = OAuth::from_env(scopes) else { bail!("OAuth::from_env failed.") };
+    let scopes = scopes!(
+        "user-library-read",
+        "playlist-modify-private",
+        "playlist-modify-public"
+    );
+    let Some(oauth) = OAuth::from_env(scopes) else {
+        bail!("OAuth::from_env failed.")
+    };
     let config = Config {
         token_refreshing: true,
         token_cached: true,
+        cache_path: token_cache_path,
         ..Default::default()
     };
 
@@ -0,0 +1,90 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fs, path::PathBuf};
+
+const APP_NAME: &str = "find-track-by-color";
+const CONFIG_FILE_NAME: &str = "config.toml";
+const DEFAULT_TOKEN_CACHE_FILE_NAME: &str = ".spotify_token_cache.json";
+
+/// `config.toml` の `[find_colors]` セクション。`FindColors` のデフォルトチューニング値
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(default)]
+pub struct FindColorsDefaults {
+    pub k: usize,
+    pub runs: usize,
+    pub max_iter: usize,
+    pub coverage: f32,
+    pub seed: usize,
+}
+
+impl Default for FindColorsDefaults {
+    fn default() -> Self {
+        FindColorsDefaults {
+            k: 8,
+            runs: 3,
+            max_iter: 20,
+            coverage: 1.0,
+            seed: 0,
+        }
+    }
+}
+
+/// `dirs::config_dir()/find-track-by-color/config.toml` から読み込むユーザー設定。
+/// CLIフラグが指定されなかった項目のデフォルト値として使う
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Config {
+    /// `--directory` 未指定時に使う画像ディレクトリ
+    pub directory: Option<PathBuf>,
+    /// `--threshold` 未指定時に使う閾値
+    pub threshold: Option<f64>,
+    /// `--limit` 未指定時に使う件数上限
+    pub limit: Option<usize>,
+    /// rspotifyのトークンキャッシュの保存先。未指定ならカレントディレクトリ直下
+    pub token_cache_path: Option<PathBuf>,
+    pub find_colors: FindColorsDefaults,
+    /// 名前から色指定文字列を引ける、名前付きパレットプリセット
+    pub palettes: HashMap<String, String>,
+}
+
+impl Config {
+    fn dir() -> Result<PathBuf> {
+        let Some(dir) = dirs::config_dir() else {
+            bail!("could not determine config dir")
+        };
+        Ok(dir.join(APP_NAME))
+    }
+
+    fn path() -> Result<PathBuf> {
+        Ok(Self::dir()?.join(CONFIG_FILE_NAME))
+    }
+
+    /// 設定ファイルを読み込む。存在しない場合はデフォルト値で新規作成してから返す
+    pub fn load_or_init() -> Result<Self> {
+        let path = Self::path()?;
+        if let Ok(content) = fs::read_to_string(&path) {
+            return Ok(toml::from_str(&content)?);
+        }
+        let config = Config::default();
+        config.save()?;
+        Ok(config)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        fs::create_dir_all(Self::dir()?)?;
+        let content = toml::to_string_pretty(self)?;
+        fs::write(Self::path()?, content)?;
+        Ok(())
+    }
+
+    /// `find` の色引数で指定された名前を、設定ファイルに保存済みの色指定文字列に解決する
+    pub fn resolve_palette<'a>(&'a self, name: &'a str) -> &'a str {
+        self.palettes.get(name).map(String::as_str).unwrap_or(name)
+    }
+
+    pub fn token_cache_path(&self) -> PathBuf {
+        self.token_cache_path
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_TOKEN_CACHE_FILE_NAME))
+    }
+}
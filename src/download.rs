@@ -0,0 +1,58 @@
+use anyhow::{bail, Result};
+use lofty::{Accessor, MimeType, Picture, PictureType, Probe, Tag, TagExt, TaggedFileExt};
+use rspotify::model::FullTrack;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncWriteExt, BufWriter};
+
+/// マッチしたトラックのプレビュー音源をダウンロードし、タイトル・アーティスト・アルバム名と
+/// マッチしたカバーアートをタグとして埋め込む
+pub async fn download_track(
+    track: &FullTrack,
+    cover_path: &Path,
+    directory: &Path,
+) -> Result<PathBuf> {
+    let Some(preview_url) = &track.preview_url else {
+        bail!("{} has no preview_url", track.name)
+    };
+    let Some(track_id) = &track.id else {
+        bail!("{} has no track id", track.name)
+    };
+
+    tokio::fs::create_dir_all(directory).await?;
+    let bytes = reqwest::get(preview_url).await?.bytes().await?;
+    let path = directory.join(track_id.to_string()).with_extension("mp3");
+    {
+        let file = tokio::fs::File::create(&path).await?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(&bytes).await?;
+        writer.flush().await?;
+    }
+
+    embed_tags(&path, track, cover_path)?;
+    Ok(path)
+}
+
+fn embed_tags(path: &Path, track: &FullTrack, cover_path: &Path) -> Result<()> {
+    let mut tagged_file = Probe::open(path)?.read()?;
+    let tag_type = tagged_file.primary_tag_type();
+    if tagged_file.primary_tag().is_none() {
+        tagged_file.insert_tag(Tag::new(tag_type));
+    }
+    let Some(tag) = tagged_file.primary_tag_mut() else {
+        bail!("no tag for {path:?}")
+    };
+
+    tag.set_title(track.name.clone());
+    if let Some(artist) = track.artists.first() {
+        tag.set_artist(artist.name.clone());
+    }
+    tag.set_album(track.album.name.clone());
+
+    let cover_bytes = std::fs::read(cover_path)?;
+    let picture =
+        Picture::new_unchecked(PictureType::CoverFront, MimeType::Jpeg, None, cover_bytes);
+    tag.set_picture(0, picture);
+
+    tag.save_to_path(path)?;
+    Ok(())
+}
@@ -1,89 +1,135 @@
+use crate::download;
+use crate::index::{ColorIndex, FileMeta};
 use crate::Color;
 use anyhow::{bail, Result};
+use crossbeam::channel::bounded;
 use image::{DynamicImage, GenericImageView, Rgb, Rgba};
 use indicatif::ProgressBar;
 use kmeans_colors::{get_kmeans, Kmeans, Sort};
 use palette::{IntoColor, Lab, Pixel, Srgb};
 use rspotify::{
-    model::{FullTrack, TrackId},
-    prelude::BaseClient,
+    model::{FullTrack, PlayableId, TrackId},
+    prelude::{BaseClient, OAuthClient},
 };
+use serde::{Deserialize, Serialize};
 use std::{
     cmp::Ordering,
-    fs::DirEntry,
     path::{Path, PathBuf},
     str::FromStr,
-    sync::Arc,
+    sync::{Arc, Mutex},
 };
 
+/// 色同士の距離の測り方
+#[derive(Clone, Copy, Debug, Default, clap::ValueEnum)]
+pub enum Metric {
+    /// 正規化RGBのユークリッド距離（旧来の挙動、後方互換用）
+    Rgb,
+    /// CIEDE2000によるLab色差。人間の知覚に近い距離になる
+    #[default]
+    Ciede2000,
+}
+
 #[derive(derive_new::new, Debug)]
-pub struct Finder<SPOTIFY: BaseClient> {
+pub struct Finder<SPOTIFY: BaseClient + OAuthClient> {
     threshold: f64,
-    target_color: Color,
+    target_colors: Vec<Color>,
+    metric: Metric,
+    threads: usize,
     limit: usize,
     directory: PathBuf,
     finder: FindColors,
+    create_playlist: Option<String>,
+    audio_directory: Option<PathBuf>,
     spotify: SPOTIFY,
 }
 
-impl<SPOTIFY: BaseClient> Finder<SPOTIFY> {
+impl<SPOTIFY: BaseClient + OAuthClient> Finder<SPOTIFY> {
     pub async fn find(self) -> Result<()> {
-        let target_color: Rgb<u8> = self.target_color.into();
-        let pb = Arc::new(ProgressBar::new(0));
-        let finder = Arc::new(self.finder);
-        let tasks = std::fs::read_dir(&self.directory)?
+        let metric = self.metric;
+        let threads = self.threads;
+        let limit = self.limit;
+        let threshold = self.threshold;
+        let target_colors = self
+            .target_colors
             .into_iter()
-            .flatten()
-            .map(|entry| {
-                let pb = pb.clone();
-                let finder = finder.clone();
-                tokio::spawn(async move {
-                    {
-                        let r =
-                            get_color_by_entry(&finder, &entry).map(|color| (entry.path(), color));
-                        pb.inc(1);
-                        r
-                    }
-                })
-            })
-            .take(self.limit)
+            .map(color_to_lab)
             .collect::<Vec<_>>();
-        pb.set_length(tasks.len() as u64);
+        let pb = Arc::new(ProgressBar::new(limit as u64));
+        let params = self.finder.params();
+        let finder = Arc::new(self.finder);
+        let index = Arc::new(Mutex::new(ColorIndex::load(&self.directory)));
+        let directory = self.directory.clone();
 
-        let results = futures::future::join_all(tasks).await;
-        let results = results.into_iter().flatten().flatten();
+        let results = {
+            let pb = pb.clone();
+            let finder = finder.clone();
+            let index = index.clone();
+            tokio::task::spawn_blocking(move || {
+                extract_palettes(&directory, limit, threads, &finder, &index, &params, &pb)
+            })
+            .await??
+        };
+        index.lock().unwrap().save(&self.directory)?;
 
         let tasks = results
             .into_iter()
             .flat_map(|(path, colors)| {
-                let diffs = colors
-                    .into_iter()
-                    .filter(|(_, per)| *per >= 0.1)
-                    .map(|(color, per)| (color_diff(&target_color, &color), per))
-                    .collect::<Vec<_>>();
-                diffs
-                    .into_iter()
-                    .find(|(diff, _)| *diff < self.threshold)
-                    .map(|(diff, per)| (path, diff, per))
+                score_palette(metric, threshold, &target_colors, &colors).map(|score| (path, score))
             })
-            .flat_map(|(path, diff, per)| {
-                track_id_by_image_path(&path).map(|id| (id, path, diff, per))
-            })
-            .map(|(track_id, path, diff, per)| {
-                get_track_with_scores(&self.spotify, track_id.clone(), (track_id, path, diff, per))
+            .flat_map(|(path, score)| track_id_by_image_path(&path).map(|id| (id, path, score)))
+            .map(|(track_id, path, score)| {
+                get_track_with_scores(&self.spotify, track_id.clone(), (track_id, path, score))
             });
         let results = futures::future::join_all(tasks).await;
         let mut tracks = results.into_iter().flatten().collect::<Vec<_>>();
-        tracks.sort_by(|(_, (_, _, a, _)), (_, (_, _, b, _))| {
-            b.partial_cmp(a).unwrap_or(Ordering::Equal)
-        });
-        for (track, (id, path, diff, per)) in tracks {
-            println!("{} ... {id}, {path:?}, {diff}, {per}", track.name);
+        tracks
+            .sort_by(|(_, (_, _, a)), (_, (_, _, b))| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+        for (track, (id, path, score)) in &tracks {
+            println!("{} ... {id}, {path:?}, {score}", track.name);
         }
+
+        if let Some(name) = &self.create_playlist {
+            let track_ids = tracks.iter().map(|(_, (id, ..))| id.clone()).collect();
+            let url = create_playlist_from_tracks(&self.spotify, name, track_ids).await?;
+            println!("created playlist: {url}");
+        }
+
+        if let Some(audio_directory) = &self.audio_directory {
+            for (track, (_, cover_path, _)) in &tracks {
+                match download::download_track(track, cover_path, audio_directory).await {
+                    Ok(path) => println!("downloaded audio: {path:?}"),
+                    Err(err) => eprintln!("failed to download audio for {}: {err}", track.name),
+                }
+            }
+        }
+
         Ok(())
     }
 }
 
+/// ランキング済みのトラックから新規プレイリストを作成し、その順番のまま曲を追加する
+async fn create_playlist_from_tracks(
+    spotify: &impl OAuthClient,
+    name: &str,
+    track_ids: Vec<TrackId>,
+) -> Result<String> {
+    let user = spotify.me().await?;
+    let playlist = spotify
+        .user_playlist_create(&user.id, name, Some(false), None, None)
+        .await?;
+    let items = track_ids
+        .iter()
+        .map(|track_id| track_id as &dyn PlayableId)
+        .collect::<Vec<_>>();
+    spotify
+        .playlist_add_items(&playlist.id, items, None)
+        .await?;
+    let Some(url) = playlist.external_urls.get("spotify") else {
+        bail!("playlist has no spotify url")
+    };
+    Ok(url.clone())
+}
+
 async fn get_track_with_scores<S: Sized>(
     spotify: &impl BaseClient,
     track_id: TrackId,
@@ -99,33 +145,268 @@ async fn get_track(spotify: &impl BaseClient, track_id: TrackId) -> Result<FullT
 }
 
 fn track_id_by_image_path(path: &Path) -> Result<TrackId> {
-    let Some(name) = path.file_name().and_then(|name| name.to_str()) else { bail!("file name none") };
-    let Some(uri) = name.strip_suffix(".jpg") else { bail!("not matched") };
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        bail!("file name none")
+    };
+    let Some(uri) = name.strip_suffix(".jpg") else {
+        bail!("not matched")
+    };
     let track_id = TrackId::from_str(uri)?;
     Ok(track_id)
 }
 
-fn get_color_by_entry(finder: &FindColors, entry: &DirEntry) -> Result<Vec<(Rgb<u8>, f32)>> {
-    let path = entry.path();
-    let img = image::open(&path)?;
+fn get_color_by_path(
+    finder: &FindColors,
+    index: &Mutex<ColorIndex>,
+    params: &FindColorsParams,
+    path: &Path,
+) -> Result<Vec<(Lab, f32)>> {
+    let meta = FileMeta::from_path(path)?;
+    let track_key = path
+        .file_stem()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default()
+        .to_string();
+
+    if let Some(colors) = index.lock().unwrap().get(&track_key, &meta, params) {
+        return Ok(colors);
+    }
+
+    let img = image::open(path)?;
     let colors = finder.get_colors(img);
+    index
+        .lock()
+        .unwrap()
+        .insert(track_key, meta, *params, &colors);
     Ok(colors)
 }
 
+/// `prepare` が保存するのは `.jpg` の画像とサイドカーの `index.json` だけなので、
+/// 拡張子でサイドカーファイルを弾いて走査対象を実画像に絞る
+fn is_image_path(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.eq_ignore_ascii_case("jpg"))
+        .unwrap_or(false)
+}
+
+/// ディレクトリを歩く producer と、rayon ワーカープールによる `get_colors` 抽出を
+/// crossbeam チャンネルでつなぐパイプライン。CPU律速なk-meansをTokioのリアクタから切り離す
+#[allow(clippy::too_many_arguments)]
+fn extract_palettes(
+    directory: &Path,
+    limit: usize,
+    threads: usize,
+    finder: &Arc<FindColors>,
+    index: &Arc<Mutex<ColorIndex>>,
+    params: &FindColorsParams,
+    pb: &Arc<ProgressBar>,
+) -> Result<Vec<(PathBuf, Vec<(Lab, f32)>)>> {
+    if threads == 0 {
+        bail!("threads must be at least 1");
+    }
+    let (path_tx, path_rx) = bounded::<PathBuf>(threads * 2);
+    let (result_tx, result_rx) = bounded::<(PathBuf, Result<Vec<(Lab, f32)>>)>(threads * 2);
+
+    let walked_directory = directory.to_path_buf();
+    let producer = std::thread::spawn(move || -> Result<()> {
+        let entries = std::fs::read_dir(&walked_directory)?
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| is_image_path(path))
+            .take(limit);
+        for path in entries {
+            if path_tx.send(path).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    });
+
+    let consumer = std::thread::spawn(move || {
+        result_rx
+            .iter()
+            .filter_map(|(path, r)| r.ok().map(|colors| (path, colors)))
+            .collect::<Vec<_>>()
+    });
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()?;
+    pool.scope(|scope| {
+        for _ in 0..threads {
+            let path_rx = path_rx.clone();
+            let result_tx = result_tx.clone();
+            scope.spawn(move |_| {
+                for path in path_rx.iter() {
+                    let r = get_color_by_path(finder, index, params, &path);
+                    pb.inc(1);
+                    let _ = result_tx.send((path, r));
+                }
+            });
+        }
+    });
+    drop(result_tx);
+
+    producer.join().expect("path-walking thread panicked")?;
+
+    Ok(consumer.join().expect("result-collecting thread panicked"))
+}
+
+/// CLIで指定された色をLab色空間に変換する
+fn color_to_lab(color: Color) -> Lab {
+    let Rgb([r, g, b]): Rgb<u8> = color.into();
+    Srgb::new(r, g, b).into_format::<f32>().into_color()
+}
+
+fn lab_to_rgb(color: &Lab) -> Rgb<u8> {
+    let srgb: Srgb = (*color).into_color();
+    let srgb = srgb.into_format::<u8>();
+    Rgb([srgb.red, srgb.green, srgb.blue])
+}
+
+/// アルバムのパレットが、要求された複数のターゲット色をどれだけ良くカバーしているかを採点する。
+/// 各ターゲット色について最も近いパレット色（`per` 10%未満のマイナーな色は無視）を探し、
+/// その色差をカバー率で割って重み付けした値を合計する（値が小さいほど良い一致）。
+/// いずれかのターゲットに `threshold` 未満で一致する色が無い場合は `None`（非該当）を返す。
+fn score_palette(
+    metric: Metric,
+    threshold: f64,
+    target_colors: &[Lab],
+    colors: &[(Lab, f32)],
+) -> Option<f64> {
+    let mut total = 0.0;
+    for target in target_colors {
+        let (diff, per) = colors
+            .iter()
+            .filter(|(_, per)| *per >= 0.1)
+            .map(|(color, per)| (color_diff(metric, target, color), *per))
+            .min_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap_or(Ordering::Equal))?;
+        if diff >= threshold {
+            return None;
+        }
+        total += diff / per as f64;
+    }
+    Some(total)
+}
+
+fn color_diff(metric: Metric, a: &Lab, b: &Lab) -> f64 {
+    match metric {
+        Metric::Rgb => rgb_color_diff(a, b),
+        Metric::Ciede2000 => ciede2000(a, b),
+    }
+}
+
 fn diff(a: u8, b: u8) -> f64 {
-    let a = a as f64;
-    let b = b as f64;
-    let a = a / (u8::MAX as f64);
-    let b = b / (u8::MAX as f64);
+    let a = a as f64 / u8::MAX as f64;
+    let b = b as f64 / u8::MAX as f64;
     a - b
 }
 
-fn color_diff(Rgb([a_r, a_g, a_b]): &Rgb<u8>, Rgb([b_r, b_g, b_b]): &Rgb<u8>) -> f64 {
-    let d_r = diff(*a_r, *b_r);
-    let d_g = diff(*a_g, *b_g);
-    let d_b = diff(*a_b, *b_b);
-    let x = (d_r.powf(2.0) + d_g.powf(2.0) + d_b.powf(2.0)).sqrt() / 3.0f64.sqrt();
-    x.abs()
+/// 旧来の正規化RGBユークリッド距離。`--metric rgb` 互換用
+fn rgb_color_diff(a: &Lab, b: &Lab) -> f64 {
+    let Rgb([a_r, a_g, a_b]) = lab_to_rgb(a);
+    let Rgb([b_r, b_g, b_b]) = lab_to_rgb(b);
+    let d_r = diff(a_r, b_r);
+    let d_g = diff(a_g, b_g);
+    let d_b = diff(a_b, b_b);
+    (d_r.powi(2) + d_g.powi(2) + d_b.powi(2)).sqrt() / 3.0f64.sqrt()
+}
+
+/// atan2からLab色空間のhue角を [0, 360) 度で求める
+fn hue_angle(a: f64, b: f64) -> f64 {
+    if a == 0.0 && b == 0.0 {
+        0.0
+    } else {
+        let h = b.atan2(a).to_degrees();
+        if h < 0.0 {
+            h + 360.0
+        } else {
+            h
+        }
+    }
+}
+
+/// CIEDE2000によるLab色差(ΔE00)
+/// https://en.wikipedia.org/wiki/Color_difference#CIEDE2000
+fn ciede2000(a: &Lab, b: &Lab) -> f64 {
+    let (l1, a1, b1) = (a.l as f64, a.a as f64, a.b as f64);
+    let (l2, a2, b2) = (b.l as f64, b.a as f64, b.b as f64);
+
+    let c1 = (a1 * a1 + b1 * b1).sqrt();
+    let c2 = (a2 * a2 + b2 * b2).sqrt();
+    let c_bar7 = ((c1 + c2) / 2.0).powi(7);
+    let g = 0.5 * (1.0 - (c_bar7 / (c_bar7 + 25f64.powi(7))).sqrt());
+
+    let a1_p = a1 * (1.0 + g);
+    let a2_p = a2 * (1.0 + g);
+    let c1_p = (a1_p * a1_p + b1 * b1).sqrt();
+    let c2_p = (a2_p * a2_p + b2 * b2).sqrt();
+
+    let h1_p = hue_angle(a1_p, b1);
+    let h2_p = hue_angle(a2_p, b2);
+
+    let delta_l_p = l2 - l1;
+    let delta_c_p = c2_p - c1_p;
+
+    let delta_h_p = if c1_p * c2_p == 0.0 {
+        0.0
+    } else {
+        let mut dh = h2_p - h1_p;
+        if dh > 180.0 {
+            dh -= 360.0;
+        } else if dh < -180.0 {
+            dh += 360.0;
+        }
+        dh
+    };
+    let delta_upper_h_p = 2.0 * (c1_p * c2_p).sqrt() * (delta_h_p.to_radians() / 2.0).sin();
+
+    let l_bar_p = (l1 + l2) / 2.0;
+    let c_bar_p = (c1_p + c2_p) / 2.0;
+    let h_bar_p = if c1_p * c2_p == 0.0 {
+        h1_p + h2_p
+    } else if (h1_p - h2_p).abs() <= 180.0 {
+        (h1_p + h2_p) / 2.0
+    } else if h1_p + h2_p < 360.0 {
+        (h1_p + h2_p + 360.0) / 2.0
+    } else {
+        (h1_p + h2_p - 360.0) / 2.0
+    };
+
+    let t = 1.0 - 0.17 * (h_bar_p - 30.0).to_radians().cos()
+        + 0.24 * (2.0 * h_bar_p).to_radians().cos()
+        + 0.32 * (3.0 * h_bar_p + 6.0).to_radians().cos()
+        - 0.20 * (4.0 * h_bar_p - 63.0).to_radians().cos();
+
+    let delta_theta = 30.0 * (-(((h_bar_p - 275.0) / 25.0).powi(2))).exp();
+    let c_bar_p7 = c_bar_p.powi(7);
+    let r_c = 2.0 * (c_bar_p7 / (c_bar_p7 + 25f64.powi(7))).sqrt();
+
+    let s_l = 1.0 + (0.015 * (l_bar_p - 50.0).powi(2)) / (20.0 + (l_bar_p - 50.0).powi(2)).sqrt();
+    let s_c = 1.0 + 0.045 * c_bar_p;
+    let s_h = 1.0 + 0.015 * c_bar_p * t;
+    let r_t = -(2.0 * delta_theta.to_radians()).sin() * r_c;
+
+    let delta_l_term = delta_l_p / s_l;
+    let delta_c_term = delta_c_p / s_c;
+    let delta_h_term = delta_upper_h_p / s_h;
+
+    (delta_l_term.powi(2)
+        + delta_c_term.powi(2)
+        + delta_h_term.powi(2)
+        + r_t * delta_c_term * delta_h_term)
+        .sqrt()
+}
+
+/// インデックスのキャッシュが有効かどうかの比較に使う、`FindColors` のうち結果に影響するパラメータ
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct FindColorsParams {
+    pub k: usize,
+    pub runs: usize,
+    pub max_iter: usize,
+    pub coverage: f32,
+    pub seed: usize,
 }
 
 #[derive(derive_builder::Builder, Debug)]
@@ -143,7 +424,17 @@ impl FindColors {
         FindColorsBuilder::default()
     }
 
-    fn get_colors(&self, img: DynamicImage) -> Vec<(Rgb<u8>, f32)> {
+    pub fn params(&self) -> FindColorsParams {
+        FindColorsParams {
+            k: self.k,
+            runs: self.runs,
+            max_iter: self.max_iter,
+            coverage: self.coverage,
+            seed: self.seed,
+        }
+    }
+
+    pub(crate) fn get_colors(&self, img: DynamicImage) -> Vec<(Lab, f32)> {
         let bytes = img
             .pixels()
             .map(|(_, _, Rgba([r, g, b, _]))| [r, g, b])
@@ -170,14 +461,9 @@ impl FindColors {
         }
         let mut colors = Lab::sort_indexed_colors(&result.centroids, &result.indices)
             .into_iter()
-            .map(|color| {
-                let per = color.percentage;
-                let color: Srgb = color.centroid.into_color();
-                let color = color.into_format::<u8>();
-                (Rgb([color.red, color.green, color.blue]), per)
-            })
+            .map(|color| (color.centroid, color.percentage))
             .collect::<Vec<_>>();
-        colors.sort_by(|(_, a), (_, b)| b.partial_cmp(&a).unwrap_or(Ordering::Equal));
+        colors.sort_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap_or(Ordering::Equal));
         colors
     }
 }
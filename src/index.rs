@@ -0,0 +1,131 @@
+use crate::find::FindColorsParams;
+use anyhow::Result;
+use palette::Lab;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+const INDEX_FILE_NAME: &str = "index.json";
+
+/// `palette::Lab` はシリアライズできないので、保存用に成分だけ写し取ったもの
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct LabColor {
+    pub l: f32,
+    pub a: f32,
+    pub b: f32,
+}
+
+impl From<Lab> for LabColor {
+    fn from(lab: Lab) -> Self {
+        LabColor {
+            l: lab.l,
+            a: lab.a,
+            b: lab.b,
+        }
+    }
+}
+
+impl From<LabColor> for Lab {
+    fn from(color: LabColor) -> Self {
+        Lab::new(color.l, color.a, color.b)
+    }
+}
+
+/// キャッシュが有効かどうかの判定に使う画像ファイルのメタ情報
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct FileMeta {
+    pub mtime_secs: u64,
+    pub size: u64,
+}
+
+impl FileMeta {
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let metadata = fs::metadata(path)?;
+        let mtime_secs = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        Ok(FileMeta {
+            mtime_secs,
+            size: metadata.len(),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct IndexEntry {
+    meta: FileMeta,
+    params: FindColorsParams,
+    colors: Vec<(LabColor, f32)>,
+}
+
+/// トラックIDごとに抽出済みパレットをキャッシュしておくサイドカーインデックス
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ColorIndex {
+    entries: HashMap<String, IndexEntry>,
+}
+
+impl ColorIndex {
+    fn path(directory: &Path) -> PathBuf {
+        directory.join(INDEX_FILE_NAME)
+    }
+
+    /// 読み込みに失敗した場合（未作成・壊れている等）は空のインデックスを返す
+    pub fn load(directory: &Path) -> Self {
+        fs::read_to_string(Self::path(directory))
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, directory: &Path) -> Result<()> {
+        let content = serde_json::to_string_pretty(self)?;
+        fs::write(Self::path(directory), content)?;
+        Ok(())
+    }
+
+    /// ファイルが変化しておらず、パラメータも一致する場合のみキャッシュ済みパレットを返す
+    pub fn get(
+        &self,
+        track_id: &str,
+        meta: &FileMeta,
+        params: &FindColorsParams,
+    ) -> Option<Vec<(Lab, f32)>> {
+        let entry = self.entries.get(track_id)?;
+        if &entry.meta != meta || &entry.params != params {
+            return None;
+        }
+        Some(
+            entry
+                .colors
+                .iter()
+                .map(|(color, per)| ((*color).into(), *per))
+                .collect(),
+        )
+    }
+
+    pub fn insert(
+        &mut self,
+        track_id: String,
+        meta: FileMeta,
+        params: FindColorsParams,
+        colors: &[(Lab, f32)],
+    ) {
+        self.entries.insert(
+            track_id,
+            IndexEntry {
+                meta,
+                params,
+                colors: colors
+                    .iter()
+                    .map(|(color, per)| ((*color).into(), *per))
+                    .collect(),
+            },
+        );
+    }
+}
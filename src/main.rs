@@ -1,20 +1,17 @@
+mod client;
+mod config;
+mod download;
+mod find;
+mod index;
+mod prepare;
+
 use anyhow::{bail, Result};
 use clap::Parser;
-use futures::{pin_mut, TryStreamExt};
-use image::{DynamicImage, GenericImageView, Rgb, Rgba};
-use indicatif::ProgressBar;
-use rspotify::{
-    model::{FullTrack, Image, TrackId},
-    prelude::{BaseClient, OAuthClient},
-    scopes, AuthCodePkceSpotify, Config, Credentials, OAuth,
-};
-use std::{
-    fs::DirEntry,
-    path::{Path, PathBuf},
-    str::FromStr,
-    sync::Arc,
-};
-use tokio::io::{AsyncWriteExt, BufWriter};
+use config::Config;
+use image::Rgb;
+use std::{path::PathBuf, str::FromStr};
+
+use find::{FindColors, Finder, Metric};
 
 #[derive(Clone, Debug)]
 struct Color(u8, u8, u8);
@@ -39,192 +36,163 @@ impl From<Color> for Rgb<u8> {
     }
 }
 
+/// カンマ区切りで複数の色を受け取るためのCLI引数。例: `"#ff0000,#00a,gold"`
+#[derive(Clone, Debug)]
+struct Colors(Vec<Color>);
+
+impl FromStr for Colors {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let colors = s
+            .split(',')
+            .map(|part| Color::from_str(part.trim()))
+            .collect::<Result<Vec<_>>>()?;
+        if colors.is_empty() {
+            bail!("no color given");
+        }
+        Ok(Colors(colors))
+    }
+}
+
 #[derive(Parser, Debug)]
 enum App {
     /// 保存済みトラック一覧からアルバム画像をわんさかダウンロードする
     #[clap(name = "prepare")]
     Prepare {
-        #[clap(short = 'd', long = "directory", default_value = "./images")]
-        directory: PathBuf,
+        /// 未指定の場合は設定ファイルの値、それも無ければ `./images` を使う
+        #[clap(short = 'd', long = "directory")]
+        directory: Option<PathBuf>,
+        /// k-meansのクラスタ数。未指定の場合は設定ファイルの値を使う
+        #[clap(short = 'k', long = "k")]
+        k: Option<usize>,
+        /// k-meansの試行回数（最良スコアを採用する）。未指定の場合は設定ファイルの値を使う
+        #[clap(long = "runs")]
+        runs: Option<usize>,
+        #[clap(long = "max-iter")]
+        max_iter: Option<usize>,
+        #[clap(long = "coverage")]
+        coverage: Option<f32>,
+        #[clap(long = "seed")]
+        seed: Option<usize>,
     },
     /// 色を指定して近いアルバムを見つける
     Find {
-        color: Color,
-        #[clap(short = 'd', long = "directory", default_value = "./images")]
-        directory: PathBuf,
-        #[clap(short = 't', long = "threshold", default_value = "10")]
-        threshold: u8,
-        #[clap(short = 'l', long = "limit", default_value = "10")]
-        limit: usize,
+        /// 検索したい色（カンマ区切りで複数指定するとパレット一致度で検索する）。
+        /// 設定ファイルの `palettes` に登録した名前も使える
+        colors: String,
+        /// 未指定の場合は設定ファイルの値、それも無ければ `./images` を使う
+        #[clap(short = 'd', long = "directory")]
+        directory: Option<PathBuf>,
+        /// 未指定の場合は設定ファイルの値、それも無ければ `10` を使う
+        #[clap(short = 't', long = "threshold")]
+        threshold: Option<f64>,
+        /// 未指定の場合は設定ファイルの値、それも無ければ `10` を使う
+        #[clap(short = 'l', long = "limit")]
+        limit: Option<usize>,
+        /// 色同士の距離の測り方
+        #[clap(value_enum, long = "metric", default_value = "ciede2000")]
+        metric: Metric,
+        /// パレット抽出(k-means)に使うワーカースレッド数
+        #[clap(long = "threads", default_value_t = num_cpus::get())]
+        threads: usize,
+        /// k-meansのクラスタ数。未指定の場合は設定ファイルの値を使う
+        #[clap(short = 'k', long = "k")]
+        k: Option<usize>,
+        /// k-meansの試行回数（最良スコアを採用する）。未指定の場合は設定ファイルの値を使う
+        #[clap(long = "runs")]
+        runs: Option<usize>,
+        #[clap(long = "max-iter")]
+        max_iter: Option<usize>,
+        #[clap(long = "coverage")]
+        coverage: Option<f32>,
+        #[clap(long = "seed")]
+        seed: Option<usize>,
+        /// マッチしたトラックをこの名前の新規プレイリストとして作成する
+        #[clap(long = "create-playlist")]
+        create_playlist: Option<String>,
+        /// マッチしたトラックのプレビュー音源もダウンロードし、カバーアートを埋め込んで保存する
+        #[clap(long = "with-audio")]
+        with_audio: bool,
+        /// `--with-audio` で保存する音源の出力先。未指定の場合は画像と同じディレクトリ
+        #[clap(long = "audio-directory")]
+        audio_directory: Option<PathBuf>,
     },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let app = App::try_parse()?;
+    let config = Config::load_or_init()?;
     match app {
-        App::Prepare { directory } => prepare(directory).await?,
+        App::Prepare {
+            directory,
+            k,
+            runs,
+            max_iter,
+            coverage,
+            seed,
+        } => {
+            let client = client::get_client(config.token_cache_path()).await?;
+            let directory = directory
+                .or(config.directory.clone())
+                .unwrap_or_else(|| PathBuf::from("./images"));
+            let find_colors = FindColors::builder()
+                .k(k.unwrap_or(config.find_colors.k))
+                .runs(runs.unwrap_or(config.find_colors.runs))
+                .max_iter(max_iter.unwrap_or(config.find_colors.max_iter))
+                .coverage(coverage.unwrap_or(config.find_colors.coverage))
+                .verbose(false)
+                .seed(seed.unwrap_or(config.find_colors.seed))
+                .build()?;
+            prepare::prepare(client, directory, find_colors).await?;
+        }
         App::Find {
-            color,
+            colors,
             directory,
             threshold,
             limit,
-        } => find_first(color, directory, threshold, limit).await?,
-    }
-    Ok(())
-}
-
-async fn find_first(color: Color, directory: PathBuf, threshold: u8, limit: usize) -> Result<()> {
-    let creds = Credentials::from_env().unwrap();
-
-    let scopes = scopes!("user-library-read");
-    let oauth = OAuth::from_env(scopes).unwrap();
-    let mut config = Config::default();
-    config.token_cached = true;
-
-    let mut spotify = AuthCodePkceSpotify::with_config(creds, oauth, config);
-
-    let url = spotify.get_authorize_url(None)?;
-    spotify.prompt_for_token(&url).await?;
-
-    spotify.write_token_cache().await?;
-
-    let target_color: Rgb<u8> = color.into();
-    let pb = Arc::new(ProgressBar::new(0));
-
-    let tasks = std::fs::read_dir(&directory)?
-        .into_iter()
-        .flatten()
-        .map(|entry| {
-            let pb = pb.clone();
-            tokio::spawn(async move {
-                {
-                    let r = get_color_by_entry(&entry).map(|color| (entry.path(), color));
-                    pb.inc(1);
-                    r
-                }
-            })
-        })
-        .take(limit)
-        .collect::<Vec<_>>();
-    pb.set_length(tasks.len() as u64);
-
-    let results = futures::future::join_all(tasks).await;
-    let results = results.into_iter().flatten().flatten();
-
-    let tasks = results
-        .into_iter()
-        .filter(|(path, color)| {
-            println!("{path:?}");
-            let Rgb(diff) = color_diff(&target_color, color);
-            diff.into_iter().all(|c| c < threshold)
-        })
-        .flat_map(|(path, _)| track_id_by_image_path(&path))
-        .map(|track_id| get_track(&spotify, track_id));
-    let results = futures::future::join_all(tasks).await;
-    let tracks = results.into_iter().flatten();
-    for track in tracks {
-        println!("{} ... {:?}", track.name, track.preview_url);
-    }
-    Ok(())
-}
-
-async fn get_track(spotify: &impl BaseClient, track_id: TrackId) -> Result<FullTrack> {
-    let track = spotify.track(&track_id).await?;
-    Ok(track)
-}
-
-fn track_id_by_image_path(path: &Path) -> Result<TrackId> {
-    let Some(name) = path.file_name().and_then(|name| name.to_str()) else { bail!("file name none") };
-    let Some(uri) = name.strip_suffix(".jpg") else { bail!("not matched") };
-    let track_id = TrackId::from_str(uri)?;
-    Ok(track_id)
-}
-
-fn get_color_by_entry(entry: &DirEntry) -> Result<Rgb<u8>> {
-    let path = entry.path();
-    let img = image::open(&path)?;
-    let color = get_one_color_by_image(img);
-    Ok(color)
-}
-
-fn diff(a: u8, b: u8) -> u8 {
-    if a > b {
-        a - b
-    } else {
-        b - a
-    }
-}
-
-fn color_diff(Rgb([a_r, a_g, a_b]): &Rgb<u8>, Rgb([b_r, b_g, b_b]): &Rgb<u8>) -> Rgb<u8> {
-    Rgb([diff(*a_r, *b_r), diff(*a_g, *b_g), diff(*a_b, *b_b)])
-}
-
-async fn prepare(directory: PathBuf) -> Result<()> {
-    let Some(creds) = Credentials::from_env() else { bail!("Credentials::from_env failed.") };
-
-    let scopes = scopes!("user-library-read");
-    dbg!(&scopes);
-    let Some(oauth) = OAuth::from_env(scopes) else { bail!("OAuth::from_env failed.") };
-    let mut config = Config::default();
-    config.token_cached = true;
-
-    let mut spotify = AuthCodePkceSpotify::with_config(creds, oauth, config);
-
-    spotify.write_token_cache().await?;
-
-    let url = spotify.get_authorize_url(None)?;
-    spotify.prompt_for_token(&url).await?;
-
-    let stream = spotify.current_user_saved_tracks(None);
-    pin_mut!(stream);
-    println!("Items (blocking):");
-
-    tokio::fs::create_dir_all(&directory).await?;
-
-    // 並列にやれるようにしたいね
-    while let Ok(Some(item)) = stream.try_next().await {
-        save_track_image(&directory, &item.track).await?;
+            metric,
+            threads,
+            k,
+            runs,
+            max_iter,
+            coverage,
+            seed,
+            create_playlist,
+            with_audio,
+            audio_directory,
+        } => {
+            let colors = Colors::from_str(config.resolve_palette(&colors))?;
+            let client = client::get_client(config.token_cache_path()).await?;
+            let directory = directory
+                .or(config.directory.clone())
+                .unwrap_or_else(|| PathBuf::from("./images"));
+            let threshold = threshold.or(config.threshold).unwrap_or(10.0);
+            let limit = limit.or(config.limit).unwrap_or(10);
+            let find_colors = FindColors::builder()
+                .k(k.unwrap_or(config.find_colors.k))
+                .runs(runs.unwrap_or(config.find_colors.runs))
+                .max_iter(max_iter.unwrap_or(config.find_colors.max_iter))
+                .coverage(coverage.unwrap_or(config.find_colors.coverage))
+                .verbose(false)
+                .seed(seed.unwrap_or(config.find_colors.seed))
+                .build()?;
+            let audio_directory =
+                with_audio.then(|| audio_directory.unwrap_or_else(|| directory.clone()));
+            let finder = Finder::new(
+                threshold,
+                colors.0,
+                metric,
+                threads,
+                limit,
+                directory,
+                find_colors,
+                create_playlist,
+                audio_directory,
+                client,
+            );
+            finder.find().await?;
+        }
     }
-
-    Ok(())
-}
-
-/// とりあえず画像を保存しまくる
-async fn save_track_image(directory: &Path, track: &FullTrack) -> Result<()> {
-    let Some(Image { url, .. }) = track.album.images.first() else { bail!("") };
-    let Some(track_id) = &track.id else { bail!("") };
-    let bytes = reqwest::get(url).await?.bytes().await?;
-    let file =
-        tokio::fs::File::create(directory.join(track_id.to_string()).with_extension("jpg")).await?;
-    let mut writer = BufWriter::new(file);
-    writer.write_all(&bytes).await?;
     Ok(())
 }
-
-/// 画像から代表になる色を一つ返す
-/// RGBそれぞれの平均をとって、合わせたものを代表としている
-/// https://artteknika.hatenablog.com/entry/2019/09/17/151412
-/// https://crates.io/crates/kmeans_colors 使えるかも?
-fn get_one_color_by_image(img: DynamicImage) -> Rgb<u8> {
-    let colors = img
-        .pixels()
-        .map(|(_, _, color)| color)
-        .into_iter()
-        .collect::<Vec<_>>();
-    let colors_len = colors.len();
-    let r = colors
-        .iter()
-        .fold(0usize, |sum, Rgba(color)| sum + color[0] as usize)
-        / colors_len;
-    let g = colors
-        .iter()
-        .fold(0usize, |sum, Rgba(color)| sum + color[1] as usize)
-        / colors_len;
-    let b = colors
-        .iter()
-        .fold(0usize, |sum, Rgba(color)| sum + color[2] as usize)
-        / colors_len;
-    let color = Rgb([r as u8, g as u8, b as u8]);
-    color
-}
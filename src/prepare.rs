@@ -1,3 +1,5 @@
+use crate::find::FindColors;
+use crate::index::{ColorIndex, FileMeta};
 use anyhow::{bail, Result};
 use futures::{pin_mut, StreamExt};
 use indicatif::ProgressBar;
@@ -7,11 +9,15 @@ use rspotify::{
 };
 use std::{
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{Arc, Mutex},
 };
 use tokio::io::{AsyncWriteExt, BufWriter};
 
-pub async fn prepare(client: impl BaseClient + OAuthClient, directory: PathBuf) -> Result<()> {
+pub async fn prepare(
+    client: impl BaseClient + OAuthClient,
+    directory: PathBuf,
+    finder: FindColors,
+) -> Result<()> {
     let stream = client.current_user_saved_tracks(None);
     pin_mut!(stream);
 
@@ -24,14 +30,22 @@ pub async fn prepare(client: impl BaseClient + OAuthClient, directory: PathBuf)
         .flatten()
         .collect::<Vec<_>>();
     let pb = Arc::new(ProgressBar::new(items.len() as u64));
+    let finder = Arc::new(finder);
+    let index = Arc::new(Mutex::new(ColorIndex::load(&directory)));
 
-    let _ = futures::future::join_all(
-        items
-            .into_iter()
-            .map(|item| save_track_image_with_pb(&directory, item.track, pb.clone())),
-    )
+    let _ = futures::future::join_all(items.into_iter().map(|item| {
+        save_track_image_with_pb(
+            &directory,
+            item.track,
+            pb.clone(),
+            finder.clone(),
+            index.clone(),
+        )
+    }))
     .await;
 
+    index.lock().unwrap().save(&directory)?;
+
     Ok(())
 }
 
@@ -39,21 +53,45 @@ async fn save_track_image_with_pb(
     directory: &Path,
     track: FullTrack,
     pb: Arc<ProgressBar>,
+    finder: Arc<FindColors>,
+    index: Arc<Mutex<ColorIndex>>,
 ) -> Result<()> {
-    let r = save_track_image(&directory, track).await;
+    let r = save_track_image(directory, track, &finder, &index).await;
     pb.inc(1);
     r?;
     Ok(())
 }
 
-/// とりあえず画像を保存しまくる
-async fn save_track_image(directory: &Path, track: FullTrack) -> Result<()> {
-    let Some(Image { url, .. }) = track.album.images.first() else { bail!("") };
+/// 画像を保存しまくる。保存と同時にパレットを抽出してインデックスも更新する
+async fn save_track_image(
+    directory: &Path,
+    track: FullTrack,
+    finder: &Arc<FindColors>,
+    index: &Mutex<ColorIndex>,
+) -> Result<()> {
+    let Some(Image { url, .. }) = track.album.images.first() else {
+        bail!("")
+    };
     let Some(track_id) = &track.id else { bail!("") };
     let bytes = reqwest::get(url).await?.bytes().await?;
-    let file =
-        tokio::fs::File::create(directory.join(track_id.to_string()).with_extension("jpg")).await?;
-    let mut writer = BufWriter::new(file);
-    writer.write_all(&bytes).await?;
+    let path = directory.join(track_id.to_string()).with_extension("jpg");
+    {
+        let file = tokio::fs::File::create(&path).await?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(&bytes).await?;
+        writer.flush().await?;
+    }
+
+    let meta = FileMeta::from_path(&path)?;
+    let img = image::load_from_memory(&bytes)?;
+    let colors = {
+        let finder = finder.clone();
+        tokio::task::spawn_blocking(move || finder.get_colors(img)).await?
+    };
+    index
+        .lock()
+        .unwrap()
+        .insert(track_id.to_string(), meta, finder.params(), &colors);
+
     Ok(())
 }